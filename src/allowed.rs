@@ -1,249 +1,199 @@
 //! Utilities for finding out what characters are allowed by parsing regexes.
 use super::*;
+use anyhow::{anyhow, Context};
+use regex_syntax::ast::{Ast, Class as AstClass};
+use regex_syntax::hir::{Class, ClassUnicode, ClassUnicodeRange, Hir, HirKind, Literal};
 
-fn byte_from_literal(literal: &regex_syntax::ast::Literal) -> u8 {
-    let c = literal.c;
-    if c < ' ' {
-        panic!("ASCII control character in regex!!!");
-    }
-    else if c >= '\u{7F}' {
-        panic!("Non-ASCII-printable-character in regex!!!!!");
-    }
-    c as u8 // safe because we excluded "high bytes"
-}
-
-fn add_all_literal(result: &mut Vec<u8>, literal: &regex_syntax::ast::Literal) {
-    let c = byte_from_literal(literal);
-    if !result.contains(&c) {
-        result.push(c);
-    }
+fn add_allowed_chars_from_literal(result: &mut ClassUnicode, literal: &Literal) {
+    let c = match *literal {
+        Literal::Unicode(c) => c,
+        Literal::Byte(b) => b as char,
+    };
+    result.push(ClassUnicodeRange::new(c, c));
 }
 
-fn add_literal(result: &mut Vec<u8>, literal: &regex_syntax::ast::Literal, all_allowed_chars: &[u8]) {
-    let c = byte_from_literal(literal);
-    if !result.contains(&c) && all_allowed_chars.contains(&c) {
-        result.push(c);
+// By the time a class reaches us, the translator has already flattened any
+// set operations the clue used ("[a-z&&[^aeiou]]" intersection, "[a-z--
+// [aeiou]]" difference, "[a-z~~c-k]" symmetric difference, arbitrarily
+// nested) down to a single list of resolved ranges, so there's no operator
+// logic left for us to apply here at all.
+fn add_allowed_chars_from_class(result: &mut ClassUnicode, class: &Class) -> anyhow::Result<()> {
+    match class {
+        Class::Unicode(unicode) => result.union(unicode),
+        // We translate with Unicode mode on by default (see
+        // `parse_and_translate`), but an inline `(?-u)` flag switches its
+        // subtree to byte mode, which hands us a class of raw bytes instead
+        // of chars. We have no way to fold a byte range into a `char`-based
+        // allowed set, so this is a real unsupported construct, not an
+        // invariant violation.
+        //
+        // In the shipped solver this can't actually fire: `Hints::from_spec`
+        // compiles every clue with `fancy_regex` first, and fancy-regex
+        // rejects `(?-u)` outright ("Disabling Unicode not supported"), so a
+        // clue using it never makes it past that point. It's still a real
+        // error return rather than an `unreachable!()`/`assert!`, though,
+        // because this module's functions are also exercised directly (see
+        // the tests below, and any future caller that parses a clue without
+        // going through `Hints::from_spec` first) -- it's defense-in-depth
+        // for those paths, not dead code.
+        Class::Bytes(_) => return Err(anyhow!(
+            "Byte-mode character classes (from an inline `(?-u)` flag) are not supported in this clue")),
     }
+    Ok(())
 }
 
-fn allowed_chars_for_perlkind(kind: &regex_syntax::ast::ClassPerlKind) -> &'static [u8] {
-    use regex_syntax::ast::ClassPerlKind;
-    match kind {
-        ClassPerlKind::Digit => b"0123456789", // "\d"
-        ClassPerlKind::Space => b" ", // "\s"
-        ClassPerlKind::Word => b"ABCDEFGHIJKLMNOPQRSTUVWXYZ", // "\w"
-    }
-}
-
-fn add_allowed_chars_from_class_set_item(result: &mut Vec<u8>, item: &regex_syntax::ast::ClassSetItem, all_allowed_chars: &[u8]) {
-    use regex_syntax::ast::ClassSetItem;
-    match item {
-        ClassSetItem::Empty(..) => (),
-        ClassSetItem::Literal(literal)
-        => add_all_literal(result, &literal),
-        ClassSetItem::Range(range) => {
-            let start = byte_from_literal(&range.start);
-            let end = byte_from_literal(&range.end);
-            assert!(end >= start);
-            for b in start ..= end {
-                if !result.contains(&b) && all_allowed_chars.contains(&b) { result.push(b) }
-            }
+fn add_allowed_chars_from_hir(result: &mut ClassUnicode, hir: &Hir) -> anyhow::Result<()> {
+    match hir.kind() {
+        // "" and "^" "$" "\b" etc: no characters of their own
+        HirKind::Empty | HirKind::Anchor(..) | HirKind::WordBoundary(..) => (),
+        // "a", "é"
+        HirKind::Literal(literal) => add_allowed_chars_from_literal(result, literal),
+        // "\s", "\p{Greek}", "[a-xz]", "."
+        HirKind::Class(class) => add_allowed_chars_from_class(result, class)?,
+        // "a{1,3}" "a?" "a*" "a+"
+        HirKind::Repetition(repetition) => {
+            add_allowed_chars_from_hir(result, &repetition.hir)?;
         },
-        ClassSetItem::Ascii(..) => panic!("No ASCII classes in regex crossword ALLOWED!"), // e.g. "[[:alnum:][:digit:]]"
-        ClassSetItem::Unicode(..) => panic!("HEY! NO UNICODE CLASSES EVEN IN SETS!"),
-        ClassSetItem::Perl(perl) => {
-            let allowed: &'static [u8] = allowed_chars_for_perlkind(&perl.kind);
-            for &b in allowed.iter() {
-                if !result.contains(&b) && all_allowed_chars.contains(&b)  { result.push(b) }
-            }
+        // "(a)", "(?i:a)": the translator already tracks `(?i)`/`(?-i)` as it
+        // walks the Ast, lexically scoped to the group they appear in, and
+        // has case-folded every literal and class range under its influence
+        // by the time it hands us this Hir. There's no flag state left for
+        // us to thread through ourselves.
+        HirKind::Group(group) => {
+            add_allowed_chars_from_hir(result, &group.hir)?;
         },
-        ClassSetItem::Bracketed(..) => panic!("No nesting of brackets in brackets! That's too brackish!"),
-        ClassSetItem::Union(union) => { // "[ab-dz]" is a union of "[a]", "[b-d]", and "[z]"
-            for item in union.items.iter() {
-                add_allowed_chars_from_class_set_item(result, item, all_allowed_chars);
+        // "abc" "(a)d(g)"
+        HirKind::Concat(hirs) |
+        // "a|b"
+        HirKind::Alternation(hirs) => {
+            for hir in hirs.iter() {
+                add_allowed_chars_from_hir(result, hir)?;
             }
         },
     }
+    Ok(())
 }
 
-fn add_all_allowed_chars_from_class_set_item(result: &mut Vec<u8>, item: &regex_syntax::ast::ClassSetItem) {
-    use regex_syntax::ast::ClassSetItem;
-    match item {
-        ClassSetItem::Empty(..) => (),
-        ClassSetItem::Literal(literal)
-        => add_all_literal(result, &literal),
-        ClassSetItem::Range(range) => {
-            let start = byte_from_literal(&range.start);
-            let end = byte_from_literal(&range.end);
-            assert!(end >= start);
-            for b in start ..= end {
-                if !result.contains(&b) { result.push(b) }
-            }
-        },
-        ClassSetItem::Ascii(..) => panic!("No ASCII classes in regex crossword ALLOWED!"), // e.g. "[[:alnum:][:digit:]]"
-        ClassSetItem::Unicode(..) => panic!("HEY! NO UNICODE CLASSES EVEN IN SETS!"),
-        ClassSetItem::Perl(perl) => {
-            let allowed: &'static [u8] = allowed_chars_for_perlkind(&perl.kind);
-            for &b in allowed.iter() {
-                if !result.contains(&b) { result.push(b) }
-            }
-        },
-        ClassSetItem::Bracketed(..) => panic!("No nesting of brackets in brackets! That's too brackish!"),
-        ClassSetItem::Union(union) => { // "[ab-dz]" is a union of "[a]", "[b-d]", and "[z]"
-            for item in union.items.iter() {
-                add_all_allowed_chars_from_class_set_item(result, item);
-            }
-        },
+// Both the parser and the translator produce span-aware errors that already
+// know how to render the offending clue with the problem spot pointed out,
+// so we just attach the clue as context rather than inventing our own
+// message.
+fn parse_ast(pattern: &str) -> anyhow::Result<Ast> {
+    let mut parser = regex_syntax::ast::parse::Parser::new();
+    parser.parse(pattern)
+        .with_context(|| format!("Couldn't parse clue: {}", pattern))
+}
+
+// We parse and translate every clue in full Unicode mode: the allowed set is
+// a canonical interval set over `char`, so there's no reason to restrict
+// clues (or the grid) to ASCII/bytes anymore. Negated classes, `.`, and the
+// like all come back as ordinary (if large) range lists.
+fn parse_and_translate(pattern: &str) -> anyhow::Result<Hir> {
+    let ast = parse_ast(pattern)?;
+    let mut translator = regex_syntax::hir::translate::Translator::new();
+    translator.translate(pattern, &ast)
+        .with_context(|| format!("Couldn't translate clue: {}", pattern))
+}
+
+fn ast_class_is_negated(class: &AstClass) -> bool {
+    match class {
+        AstClass::Perl(perl) => perl.negated,
+        // `is_negated` (rather than the raw `negated` field) accounts for
+        // `\P{scx!=Katakana}`-style classes, where the class is written
+        // negated but the comparison operator already un-negates it.
+        AstClass::Unicode(unicode) => unicode.is_negated(),
+        AstClass::Bracketed(bracketed) => bracketed.negated,
     }
 }
 
-fn add_all_allowed_chars_from_ast(result: &mut Vec<u8>, ast: &Ast) {
-    use Ast::*;
-    use regex_syntax::ast::Class;   
+// Resolve a literal's or a non-negated class's own source text (sliced out
+// of `pattern` by its span) through the Hir-based machinery above. If an
+// enclosing `(?i)`/`(?i:...)` scope is active, we re-apply it to the slice
+// before resolving, since the slice on its own has no idea it was ever
+// inside that scope.
+fn resolve_case_folded(result: &mut ClassUnicode, pattern: &str, span: &regex_syntax::ast::Span, case_insensitive: bool) -> anyhow::Result<()> {
+    let text = &pattern[span.start.offset .. span.end.offset];
+    let hir = if case_insensitive {
+        parse_and_translate(&format!("(?i){}", text))?
+    } else {
+        parse_and_translate(text)?
+    };
+    add_allowed_chars_from_hir(result, &hir)
+}
+
+// Building the puzzle's overall alphabet is a different question from "what
+// does this one hint allow" (`add_allowed_chars_from_hir`): a hint using
+// `.` or a negated class like `\D`/`[^...]` matches almost any character, so
+// folding its fully-resolved range into the master alphabet would make that
+// one hint swamp whatever real alphabet the puzzle's literal hints
+// establish. So, like the pre-HIR walk this replaced, we walk the
+// untranslated `Ast` here (not the `Hir`) so we can see which classes were
+// actually written as negated and skip them; `.` is skipped the same way.
+// For a literal or a class that *isn't* negated, we still want set
+// operations, Unicode properties, and -- since the Ast doesn't case-fold
+// anything for us the way the Hir does -- `(?i)` case folding resolved, so
+// we hand just that node's own source text off to the Hir-based machinery
+// above, re-applying `case_insensitive` (tracked as we walk, since a
+// `(?i)`/`(?-i)` lasts until the end of the group it appears in) if it's
+// active.
+fn add_all_allowed_chars_from_ast(result: &mut ClassUnicode, pattern: &str, ast: &Ast, case_insensitive: &mut bool) -> anyhow::Result<()> {
     match ast {
-        // "a|b"
-        Alternation(alternation) => {
-            for ast in alternation.asts.iter() {
-                add_all_allowed_chars_from_ast(result, ast);
+        // "" and "^" "$" "\b" etc: no characters of their own
+        Ast::Empty(..) | Ast::Assertion(..) => (),
+        // the "i" in "(?i)": affects everything after it, across any `|`,
+        // up to the end of the enclosing group
+        Ast::Flags(set_flags) => {
+            if let Some(state) = set_flags.flags.flag_state(regex_syntax::ast::Flag::CaseInsensitive) {
+                *case_insensitive = state;
             }
         },
-        // "\s", "\p{Greek}", "[a-xz]"
-        Class(class) => match class {
-            Class::Unicode(_unicode) => {
-                panic!("No unicode classes in regex crossword ALLOWED!")
-            },
-            Class::Perl(perl) => {
-                // "\s" = only space, "\S" = anything BUT space
-                if !perl.negated {
-                    let allowed: &'static [u8] = allowed_chars_for_perlkind(&perl.kind);
-                    for &b in allowed.iter() {
-                        if !result.contains(&b) { result.push(b) }
-                    }
-                }
-            },
-            Class::Bracketed(bracketed) => {
-                if !bracketed.negated {
-                    use regex_syntax::ast::ClassSet;
-                    match &bracketed.kind {
-                        ClassSet::Item(item) => {
-                            add_all_allowed_chars_from_class_set_item(result, &item);
-                        },
-                        ClassSet::BinaryOp(..) => {
-                            panic!("No binary ops in regex crossword ALLOWED!");
-                        },
-                    }
-                }
-            },
-        },
-        // "abc" "(a)d(g)"
-        Concat(concat) => {
-            for ast in concat.asts.iter() {
-                add_all_allowed_chars_from_ast(result, ast);
-            }
+        // "." matches almost any character -- see above.
+        Ast::Dot(..) => (),
+        // "a", "é"
+        Ast::Literal(literal) => if *case_insensitive {
+            resolve_case_folded(result, pattern, &literal.span, true)?;
+        } else {
+            result.push(ClassUnicodeRange::new(literal.c, literal.c));
         },
-        // "(a)"
-        Group(group) => {
-            add_all_allowed_chars_from_ast(result, group.ast.as_ref());   
+        // "\s", "\p{Greek}", "[a-xz]"
+        Ast::Class(class) => if !ast_class_is_negated(class) {
+            resolve_case_folded(result, pattern, class.span(), *case_insensitive)?;
         },
-        // "a"
-        Literal(literal) => add_all_literal(result, literal),
         // "a{1,3}" "a?" "a*" "a+"
-        Repetition(repetition) => {
-            add_all_allowed_chars_from_ast(result, repetition.ast.as_ref());
-        },
-        // and the ignored cases
-        // ""
-        Empty(..) => (),
-        // the "i" in "(?i)"
-        Flags(..) => (),
-        // "."
-        Dot(..) => (),
-        // "^" "$"
-        Assertion(..) => (),
-    }
-}
-
-fn add_allowed_chars_from_ast(result: &mut Vec<u8>, ast: &Ast, all_allowed_chars: &[u8]) {
-    use Ast::*;
-    use regex_syntax::ast::Class;   
-    match ast {
-        // "a|b"
-        Alternation(alternation) => {
-            for ast in alternation.asts.iter() {
-                add_allowed_chars_from_ast(result, ast, all_allowed_chars);
-            }
+        Ast::Repetition(repetition) => {
+            add_all_allowed_chars_from_ast(result, pattern, &repetition.ast, case_insensitive)?;
         },
-        // "\s", "\p{Greek}", "[a-xz]"
-        Class(class) => match class {
-            Class::Unicode(_unicode) => {
-                panic!("No unicode classes in regex crossword ALLOWED!")
-            },
-            Class::Perl(perl) => {
-                // "\s" = only space, "\S" = anything BUT space
-                if !perl.negated {
-                    let allowed: &'static [u8] = allowed_chars_for_perlkind(&perl.kind);
-                    for &b in allowed.iter() {
-                        if !result.contains(&b) && all_allowed_chars.contains(&b) { result.push(b) }
-                    }
-                }
-                else {
-                    result.clear();
-                    result.extend_from_slice(all_allowed_chars);
-                }
-            },
-            Class::Bracketed(bracketed) => {
-                if !bracketed.negated {
-                    use regex_syntax::ast::ClassSet;
-                    match &bracketed.kind {
-                        ClassSet::Item(item) => {
-                            add_allowed_chars_from_class_set_item(result, &item, all_allowed_chars);
-                        },
-                        ClassSet::BinaryOp(..) => {
-                            panic!("No binary ops in regex crossword ALLOWED!");
-                        },
-                    }
-                }
-                else {
-                    result.clear();
-                    result.extend_from_slice(all_allowed_chars);
-                }
-            },
+        // "(a)", "(?i:a)"
+        Ast::Group(group) => {
+            // Only a non-capturing group can carry its own flags (`(?i:a)`);
+            // a bare `(a)` just inherits whatever was already active. Either
+            // way, the group is its own scope: what's set here (directly or
+            // via a `Flags` sibling inside it) doesn't leak back out to our
+            // caller.
+            let mut inner_case_insensitive = group.flags()
+                .and_then(|flags| flags.flag_state(regex_syntax::ast::Flag::CaseInsensitive))
+                .unwrap_or(*case_insensitive);
+            add_all_allowed_chars_from_ast(result, pattern, &group.ast, &mut inner_case_insensitive)?;
         },
-        // "abc" "(a)d(g)"
-        Concat(concat) => {
+        // "abc" "(a)d(g)": a `(?i)`/`(?-i)` among these updates
+        // `case_insensitive` in place (see the `Flags` arm above), so the
+        // siblings after it, and any later `|` branches, see the new state.
+        Ast::Concat(concat) => {
             for ast in concat.asts.iter() {
-                add_allowed_chars_from_ast(result, ast, all_allowed_chars);
+                add_all_allowed_chars_from_ast(result, pattern, ast, case_insensitive)?;
             }
         },
-        // "."
-        Dot(..) => {
-            // It would be more efficient if we would bail out of any subsequent
-            // parsing of the AST at this point, but that's too much work and
-            // it will be a tiny portion of our runtime anyway.
-            result.clear();
-            result.extend_from_slice(all_allowed_chars);
-        },
-        // "(a)"
-        Group(group) => {
-            add_allowed_chars_from_ast(result, group.ast.as_ref(), all_allowed_chars);   
-        },
-        // "a"
-        Literal(literal) => add_literal(result, literal, all_allowed_chars),
-        // "a{1,3}" "a?" "a*" "a+"
-        Repetition(repetition) => {
-            add_allowed_chars_from_ast(result, repetition.ast.as_ref(), all_allowed_chars);
+        // "a|b"
+        Ast::Alternation(alternation) => {
+            for ast in alternation.asts.iter() {
+                add_all_allowed_chars_from_ast(result, pattern, ast, case_insensitive)?;
+            }
         },
-        // and the ignored cases
-        // ""
-        Empty(..) => (),
-        // the "i" in "(?i)"
-        Flags(..) => (),
-        // "^" "$"
-        Assertion(..) => (),
     }
+    Ok(())
 }
 
-fn add_all_allowed_chars(result: &mut Vec<u8>, hints: Option<&Vec<Option<String>>>) {
+fn add_all_allowed_chars(result: &mut ClassUnicode, hints: Option<&Vec<Option<String>>>) -> anyhow::Result<()> {
     if let Some(hints) = hints {
         for hint in hints.iter() {
             if let Some(hint) = hint {
@@ -253,55 +203,39 @@ fn add_all_allowed_chars(result: &mut Vec<u8>, hints: Option<&Vec<Option<String>
                 // doing is parsing what characters are allowed, and
                 // backreferences can't add to that set!
                 let stripped_hint = BACKREFERENCE_STRIPPING_REGEX.replace_all(hint, "");
-                let mut parser = regex_syntax::ast::parse::Parser::new();
-                let ast = parser.parse(&stripped_hint).unwrap();
-                add_all_allowed_chars_from_ast(result, &ast);
+                let ast = parse_ast(&stripped_hint)?;
+                add_all_allowed_chars_from_ast(result, &stripped_hint, &ast, &mut false)?;
             }
         }
     }
+    Ok(())
 }
 
-pub(crate) fn get_all_allowed_chars(spec: &PuzzleSpec) -> anyhow::Result<Vec<u8>> {
-    let mut result = vec![];
-    add_all_allowed_chars(&mut result, spec.top_hints.as_ref());
-    add_all_allowed_chars(&mut result, spec.bottom_hints.as_ref());
-    add_all_allowed_chars(&mut result, spec.left_hints.as_ref());
-    add_all_allowed_chars(&mut result, spec.right_hints.as_ref());
-    result.sort();
+pub(crate) fn get_all_allowed_chars(spec: &PuzzleSpec) -> anyhow::Result<ClassUnicode> {
+    let mut result = ClassUnicode::empty();
+    add_all_allowed_chars(&mut result, spec.top_hints.as_ref())?;
+    add_all_allowed_chars(&mut result, spec.bottom_hints.as_ref())?;
+    add_all_allowed_chars(&mut result, spec.left_hints.as_ref())?;
+    add_all_allowed_chars(&mut result, spec.right_hints.as_ref())?;
     Ok(result)
 }
 
-fn get_allowed_chars(hint: &str, all_allowed_chars: &[u8]) -> anyhow::Result<Vec<u8>> {
+fn get_allowed_chars(hint: &str, all_allowed_chars: &ClassUnicode) -> anyhow::Result<ClassUnicode> {
     let stripped_hint = BACKREFERENCE_STRIPPING_REGEX.replace_all(hint, "");
-    let mut parser = regex_syntax::ast::parse::Parser::new();
-    let ast = parser.parse(&stripped_hint).unwrap();
-    let mut result = Vec::with_capacity(all_allowed_chars.len());
-    add_allowed_chars_from_ast(&mut result, &ast, all_allowed_chars);
-    result.sort();
+    let hir = parse_and_translate(&stripped_hint)?;
+    let mut result = ClassUnicode::empty();
+    add_allowed_chars_from_hir(&mut result, &hir)?;
+    result.intersect(all_allowed_chars);
     Ok(result)
 }
 
-pub fn allowed_char_intersection(mut a: &[u8], mut b: &[u8]) -> Vec<u8> {
-    let mut result = Vec::with_capacity(a.len().min(b.len()));
-    while !a.is_empty() && !b.is_empty() {
-        let ac = a[0];
-        let bc = b[0];
-        if ac == bc {
-            result.push(ac);
-            a = &a[1..];
-            b = &b[1..];
-        }
-        else if ac < bc {
-            a = &a[1..];
-        }
-        else if bc < ac {
-            b = &b[1..];
-        }
-    }
+pub fn allowed_char_intersection(a: &ClassUnicode, b: &ClassUnicode) -> ClassUnicode {
+    let mut result = a.clone();
+    result.intersect(b);
     result
 }
 
-pub(crate) fn get_both_allowed_chars(hint_a: Option<&String>, hint_b: Option<&String>, all_allowed_chars: &[u8]) -> anyhow::Result<Vec<u8>> {
+pub(crate) fn get_both_allowed_chars(hint_a: Option<&String>, hint_b: Option<&String>, all_allowed_chars: &ClassUnicode) -> anyhow::Result<ClassUnicode> {
     match (hint_a, hint_b) {
         (None, None) => panic!("No hint for this row/column!?"),
         (Some(hint), None) | (None, Some(hint)) => get_allowed_chars(hint, all_allowed_chars),
@@ -313,3 +247,118 @@ pub(crate) fn get_both_allowed_chars(hint_a: Option<&String>, hint_b: Option<&St
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed_for(pattern: &str) -> ClassUnicode {
+        let hir = parse_and_translate(pattern).unwrap();
+        let mut result = ClassUnicode::empty();
+        add_allowed_chars_from_hir(&mut result, &hir).unwrap();
+        result
+    }
+
+    fn class_contains(class: &ClassUnicode, c: char) -> bool {
+        class.ranges().iter().any(|range| range.start() <= c && c <= range.end())
+    }
+
+    fn spec_with_hints(hints: Vec<&str>) -> PuzzleSpec {
+        let hints = Some(hints.into_iter().map(|h| Some(h.to_string())).collect());
+        PuzzleSpec {
+            width: 1, height: 1,
+            top_hints: hints, bottom_hints: None, left_hints: None, right_hints: None,
+        }
+    }
+
+    #[test]
+    fn master_alphabet_ignores_dot_and_negated_classes() {
+        // A `.` or negated-class hint shouldn't grow the puzzle's overall
+        // alphabet beyond what the literal hints actually establish -- it
+        // should defer entirely to them instead.
+        let spec = spec_with_hints(vec!["abc", ".", "\\D"]);
+        let allowed = get_all_allowed_chars(&spec).unwrap();
+        assert!(class_contains(&allowed, 'a'));
+        assert!(class_contains(&allowed, 'b'));
+        assert!(class_contains(&allowed, 'c'));
+        assert!(!class_contains(&allowed, 'd'));
+        assert!(!class_contains(&allowed, 'Z'));
+        assert!(!class_contains(&allowed, '0'));
+    }
+
+    #[test]
+    fn master_alphabet_resolves_non_negated_set_operations() {
+        // A non-negated class that merely *contains* a negation (like the
+        // `&&[^aeiou]]` here) isn't the "almost anything" case above, so it
+        // should still contribute its resolved (bounded) characters.
+        let spec = spec_with_hints(vec!["[a-z&&[^aeiou]]"]);
+        let allowed = get_all_allowed_chars(&spec).unwrap();
+        assert!(class_contains(&allowed, 'b'));
+        assert!(!class_contains(&allowed, 'a'));
+    }
+
+    #[test]
+    fn master_alphabet_honors_case_insensitive_flag_for_classes_and_literals() {
+        // Regression test: the master-alphabet walk used to resolve a class
+        // (or literal) by reslicing just its own span out of the clue, which
+        // threw away any enclosing `(?i)` scope and silently dropped the
+        // upper-case half of the alphabet.
+        let spec = spec_with_hints(vec!["(?i)[a-f]", "(?i)z"]);
+        let allowed = get_all_allowed_chars(&spec).unwrap();
+        assert!(class_contains(&allowed, 'a'));
+        assert!(class_contains(&allowed, 'f'));
+        assert!(class_contains(&allowed, 'A'));
+        assert!(class_contains(&allowed, 'F'));
+        assert!(class_contains(&allowed, 'z'));
+        assert!(class_contains(&allowed, 'Z'));
+    }
+
+    #[test]
+    fn master_alphabet_scopes_case_insensitive_flag_to_its_group() {
+        let spec = spec_with_hints(vec!["(?i:[a-f])g"]);
+        let allowed = get_all_allowed_chars(&spec).unwrap();
+        assert!(class_contains(&allowed, 'A'));
+        assert!(class_contains(&allowed, 'a'));
+        assert!(class_contains(&allowed, 'g'));
+        assert!(!class_contains(&allowed, 'G'));
+    }
+
+    #[test]
+    fn class_set_intersection_excludes_vowels() {
+        let allowed = allowed_for("[a-z&&[^aeiou]]");
+        assert!(class_contains(&allowed, 'b'));
+        assert!(class_contains(&allowed, 'z'));
+        assert!(!class_contains(&allowed, 'a'));
+        assert!(!class_contains(&allowed, 'e'));
+    }
+
+    #[test]
+    fn class_set_difference_and_symmetric_difference() {
+        let difference = allowed_for("[a-f--c-e]");
+        assert!(class_contains(&difference, 'b'));
+        assert!(class_contains(&difference, 'f'));
+        assert!(!class_contains(&difference, 'd'));
+
+        let symmetric = allowed_for("[a-f~~c-k]");
+        assert!(class_contains(&symmetric, 'a'));
+        assert!(class_contains(&symmetric, 'g'));
+        assert!(!class_contains(&symmetric, 'd'));
+    }
+
+    #[test]
+    fn case_insensitive_flag_adds_both_cases() {
+        let allowed = allowed_for("(?i)[a-f]");
+        assert!(class_contains(&allowed, 'a'));
+        assert!(class_contains(&allowed, 'f'));
+        assert!(class_contains(&allowed, 'A'));
+        assert!(class_contains(&allowed, 'F'));
+    }
+
+    #[test]
+    fn case_insensitive_flag_is_scoped_to_its_group() {
+        let allowed = allowed_for("(?i:[a-f])g");
+        assert!(class_contains(&allowed, 'A'));
+        assert!(class_contains(&allowed, 'a'));
+        assert!(class_contains(&allowed, 'g'));
+        assert!(!class_contains(&allowed, 'G'));
+    }
+}
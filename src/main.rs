@@ -6,7 +6,7 @@ use std::{
 use anyhow::{anyhow, Context};
 use fancy_regex::Regex;
 use once_cell::sync::Lazy;
-use regex_syntax::ast::Ast;
+use regex_syntax::hir::{ClassUnicode, ClassUnicodeRange};
 use serde::Deserialize;
 
 mod allowed;
@@ -33,10 +33,43 @@ struct Hints {
     right: Vec<Option<Regex>>,
 }
 
+// How many individual chars a `ClassUnicode`'s ranges cover, without
+// enumerating them. A clue like "." covers over a million chars as just a
+// couple of ranges; we want to keep counting them that cheap.
+fn class_char_count(class: &ClassUnicode) -> u64 {
+    class.ranges().iter()
+        .map(|range| range.end() as u32 as u64 - range.start() as u32 as u64 + 1)
+        .sum()
+}
+
+// Above this many chars, materializing a cell's allowed set (and then
+// brute-forcing every combination across its row/column) stops being "a
+// handful of ranges" and starts being a multi-hour loop. We shouldn't
+// normally get anywhere near this -- the master alphabet is supposed to
+// keep cells bounded to the puzzle's real alphabet -- but a pathological or
+// buggy clue shouldn't be able to hang the solver; fail loudly instead.
+const MAX_MATERIALIZED_CHARS: u64 = 100_000;
+
+// Enumerate the individual chars a `ClassUnicode` covers. Only call this
+// where we actually need to index/iterate specific chars (the brute-force
+// loops below) -- not for every cell up front, since that would explode a
+// handful of ranges into possibly a million-plus `char`s.
+fn materialize_class(class: &ClassUnicode) -> anyhow::Result<Vec<char>> {
+    let count = class_char_count(class);
+    if count > MAX_MATERIALIZED_CHARS {
+        return Err(anyhow!(
+            "A cell allows {} possible characters, which is too many to brute-force \
+             (is a clue using `.` or a negated class too permissively?)", count));
+    }
+    Ok(class.ranges().iter()
+        .flat_map(|range| range.start() ..= range.end())
+        .collect())
+}
+
 struct Board {
     width: usize,
     height: usize,
-    allowed_chars: Vec<Vec<u8>>,
+    allowed_chars: Vec<ClassUnicode>,
     undecided_cells: Vec<(usize, usize)>,
     // Row and column complexity for each cell
     tree_complexity: Vec<(f64, f64)>,
@@ -100,7 +133,7 @@ impl Hints {
 }
 
 impl Board {
-    fn new(width: usize, height: usize, row_allowed_chars: Vec<Vec<u8>>, col_allowed_chars: Vec<Vec<u8>>) -> Board {
+    fn new(width: usize, height: usize, row_allowed_chars: Vec<ClassUnicode>, col_allowed_chars: Vec<ClassUnicode>) -> Board {
         let mut board = Board {
             width, height,
             allowed_chars: Vec::with_capacity(width * height),
@@ -114,18 +147,19 @@ impl Board {
         for y in 0 .. height {
             for x in 0 .. width {
                 let allowed = allowed_char_intersection(&row_allowed_chars[y], &col_allowed_chars[x]);
-                if(allowed.len() == 0) {
+                let allowed_count = class_char_count(&allowed);
+                if allowed_count == 0 {
                     print!("\x1B[33;7m");
                     board.print_cell(x, y, '0');
                     print!("\x1B[0m");
                     let _ = std::io::stdout().flush();
                     println!("Cell {},{} had no possibilities!", x + 1, y + 1);
-                    println!("Row: {:?}", row_allowed_chars[y].iter().map(|&x| x as char).collect::<String>());
-                    println!("Col: {:?}", col_allowed_chars[x].iter().map(|&x| x as char).collect::<String>());
+                    println!("Row: {:?}", row_allowed_chars[y]);
+                    println!("Col: {:?}", col_allowed_chars[x]);
                     std::process::exit(1);
                 }
-                else if allowed.len() == 1 {
-                    board.print_cell(x, y, allowed[0] as char);
+                else if allowed_count == 1 {
+                    board.print_cell(x, y, allowed.ranges()[0].start());
                 }
                 else {
                     board.undecided_cells.push((x, y));
@@ -141,20 +175,20 @@ impl Board {
         // of that cell.)
         for y in 0 .. height {
             for x in 0 .. width {
-                if board.allowed_chars(x, y).len() == 1 {
+                if class_char_count(board.allowed_chars(x, y)) == 1 {
                     // Wellp.
                     board.tree_complexity.push((1.0, 1.0));
                 }
                 else {
-                    let mut row_complexity = board.allowed_chars(x, y).len() as f64;
+                    let mut row_complexity = class_char_count(board.allowed_chars(x, y)) as f64;
                     for x in 0 .. width {
-                        row_complexity *= board.allowed_chars(x, y).len() as f64;
+                        row_complexity *= class_char_count(board.allowed_chars(x, y)) as f64;
                     }
                     // Or, the Rusty way!
                     let col_complexity = (0 .. height)
                     .map(|y| {
-                        board.allowed_chars(x, y).len()
-                    }).fold(board.allowed_chars(x, y).len() as f64, |a, len| a * len as f64);
+                        class_char_count(board.allowed_chars(x, y))
+                    }).fold(class_char_count(board.allowed_chars(x, y)) as f64, |a, len| a * len as f64);
                     board.tree_complexity.push((row_complexity, col_complexity));
                 }
             }
@@ -164,15 +198,15 @@ impl Board {
     fn recalculate_tree_complexity(&mut self, x: usize, y: usize) {
         let row_complexity = (0 .. self.width)
         .map(|x| {
-            self.allowed_chars(x, y).len()
-        }).fold(self.allowed_chars(x, y).len() as f64, |a, len| a * len as f64);
+            class_char_count(self.allowed_chars(x, y))
+        }).fold(class_char_count(self.allowed_chars(x, y)) as f64, |a, len| a * len as f64);
         let col_complexity = (0 .. self.height)
         .map(|y| {
-            self.allowed_chars(x, y).len()
-        }).fold(self.allowed_chars(x, y).len() as f64, |a, len| a * len as f64);
+            class_char_count(self.allowed_chars(x, y))
+        }).fold(class_char_count(self.allowed_chars(x, y)) as f64, |a, len| a * len as f64);
         self.tree_complexity[x + y * self.width] = (row_complexity, col_complexity);
     }
-    fn allowed_chars(&self, x: usize, y: usize) -> &Vec<u8> {
+    fn allowed_chars(&self, x: usize, y: usize) -> &ClassUnicode {
         assert!(x < self.width && y < self.height);
         &self.allowed_chars[x + y * self.width]
     }
@@ -199,7 +233,7 @@ impl Board {
         *best_choice = Some(candidate);
     }
     // Returns true if any progress was made
-    fn make_progress(&mut self, hints: &Hints) -> bool {
+    fn make_progress(&mut self, hints: &Hints) -> anyhow::Result<bool> {
         // Find the LOWEST tree complexity in the unsolved portion.
         let mut best_choice: Option<Choice> = None;
         for (index, &(x, y)) in self.undecided_cells.iter().enumerate() {
@@ -213,27 +247,30 @@ impl Board {
             self.maybe_best_choice(&mut best_choice, row_choice);
             self.maybe_best_choice(&mut best_choice, col_choice);
         }
-        if best_choice.is_none() { return false } // No progress is possible.
-        let Choice { x, y, index, is_column, complexity } = best_choice.unwrap();
+        if best_choice.is_none() { return Ok(false) } // No progress is possible.
+        let Choice { x, y, index, is_column, complexity: _ } = best_choice.unwrap();
         self.print_cell(x, y, if is_column { '|' } else { '-' });
         // Brute force that cell! Find out all ACTUALLY possible characters!
-        let possible = self.allowed_chars(x, y);
+        // This is the one place we actually need to index/iterate individual
+        // chars, so (and only here) we materialize the handful of cells
+        // involved out of their compact range representation.
+        let possible = materialize_class(self.allowed_chars(x, y))?;
         let mut really_possible = Vec::with_capacity(possible.len());
-        let possibilities: Vec<&Vec<u8>>;
+        let possibilities: Vec<Vec<char>>;
         let open_cell: Vec<bool>;
         let mut big_number: Vec<usize>;
-        let mut buf: Vec<u8>;
+        let mut buf: Vec<char>;
         if is_column {
             // it's a column
-            possibilities = (0 .. self.height).map(|y| self.allowed_chars(x, y)).collect();
+            possibilities = (0 .. self.height).map(|y| materialize_class(self.allowed_chars(x, y))).collect::<anyhow::Result<_>>()?;
             open_cell = (0 .. self.height).map(|cell_y| cell_y != y && possibilities[cell_y].len() > 1).collect();
             big_number = vec![0; self.height];
-            buf = vec![0; self.height];
+            buf = vec!['\0'; self.height];
             for &ch in possible.iter() {
                 // Clear the big number and the buffer
                 for y in 0 .. self.height {
                     big_number[y] = 0;
-                    buf[y] = self.allowed_chars(x, y)[0];
+                    buf[y] = possibilities[y][0];
                 }
                 // Put the character we're brute forcing in the right slot
                 buf[y] = ch;
@@ -241,7 +278,8 @@ impl Board {
                 let mut any_allowed = false;
                 'trying_col: while !any_allowed {
                     // Try this string!
-                    let as_str = unsafe { std::str::from_utf8_unchecked(&buf) };
+                    let as_str: String = buf.iter().collect();
+                    let as_str = as_str.as_str();
                     let mut allowed = true;
                     if let Some(ref hint) = hints.top[x] {
                         if !hint.is_match(as_str).unwrap() {
@@ -283,15 +321,15 @@ impl Board {
         }
         else {
             // it's a row
-            possibilities = (0 .. self.width).map(|x| self.allowed_chars(x, y)).collect();
+            possibilities = (0 .. self.width).map(|x| materialize_class(self.allowed_chars(x, y))).collect::<anyhow::Result<_>>()?;
             open_cell = (0 .. self.width).map(|cell_x| cell_x != x && possibilities[cell_x].len() > 1).collect();
             big_number = vec![0; self.width];
-            buf = vec![0; self.width];
+            buf = vec!['\0'; self.width];
             for &ch in possible.iter() {
                 // Clear the big number and the buffer
                 for x in 0 .. self.width {
                     big_number[x] = 0;
-                    buf[x] = self.allowed_chars(x, y)[0];
+                    buf[x] = possibilities[x][0];
                 }
                 // Put the character we're brute forcing in the right slot
                 buf[x] = ch;
@@ -299,7 +337,8 @@ impl Board {
                 let mut any_allowed = false;
                 'trying_row: while !any_allowed {
                     // Try this string!
-                    let as_str = unsafe { std::str::from_utf8_unchecked(&buf) };
+                    let as_str: String = buf.iter().collect();
+                    let as_str = as_str.as_str();
                     let mut allowed = true;
                     if let Some(ref hint) = hints.left[y] {
                         if !hint.is_match(as_str).unwrap() {
@@ -339,9 +378,6 @@ impl Board {
                 }
             }
         }
-        drop(possible);
-        drop(possibilities);
-        // now we don't have ourselves borrowed anymore...
         if really_possible.len() == possible.len() {
             // Add to blacklist, so that next time we try the next most complex thing
             self.print_cell(x, y, '?'); // cells we've tried but not yet resolved will look like non-dim ? now
@@ -360,19 +396,23 @@ impl Board {
                 print!("\x1B[0m");
                 let _ = std::io::stdout().flush();
                 println!("Cell {},{} by {} ran out of possibilities!", x + 1, y + 1, if is_column { "column" } else { "row" });
-                println!("Started with: {:?}", self.allowed_chars(x, y).iter().map(|&x| x as char).collect::<String>());
+                println!("Started with: {:?}", self.allowed_chars(x, y));
                 std::process::exit(1);
             }
             else if really_possible.len() == 1 {
                 self.undecided_cells.remove(index); // this is why we needed index
                 print!("\x1B[1;32m");
-                self.print_cell(x, y, really_possible[0] as char);
+                self.print_cell(x, y, really_possible[0]);
                 print!("\x1B[0m");
             }
             else {
                 self.print_cell(x, y, '?');
             }
-            self.allowed_chars[x + y * self.width] = really_possible;
+            let mut really_possible_class = ClassUnicode::empty();
+            for &ch in really_possible.iter() {
+                really_possible_class.push(ClassUnicodeRange::new(ch, ch));
+            }
+            self.allowed_chars[x + y * self.width] = really_possible_class;
             // Now correct the whole row's (or column's) tree complexity, because
             // we have changed the values for everything in our row/column!
             if is_column {
@@ -388,7 +428,7 @@ impl Board {
         }
         // we didn't necessarily make progress, but we made progress toward
         // making progress!
-        true
+        Ok(true)
     }
     // This is NOT upside down because we know how tall we are
     fn print_cell(&self, x: usize, y: usize, wat: char) {
@@ -424,26 +464,25 @@ fn main() -> anyhow::Result<()> {
     let start_time = Instant::now();
     let hints = Hints::from_spec(&spec)?;
     let all_allowed_chars = get_all_allowed_chars(&spec)?;
-    println!("Here are all the allowed chars we found: {:?}",
-             all_allowed_chars.iter().map(|x| *x as char).collect::<String>());
-    let row_allowed_chars: Vec<Vec<u8>> = (0 .. spec.height).map(|y| {
+    println!("Here are all the allowed chars we found: {:?}", all_allowed_chars);
+    let row_allowed_chars: Vec<ClassUnicode> = (0 .. spec.height).map(|y| {
         // For each row...
         let left_hint = spec.left_hints.as_ref().and_then(|x| x.get(y)).and_then(|x| x.as_ref());
         let right_hint = spec.right_hints.as_ref().and_then(|x| x.get(y)).and_then(|x| x.as_ref());
-        get_both_allowed_chars(left_hint, right_hint, &all_allowed_chars).unwrap()
-    }).collect();
-    let col_allowed_chars: Vec<Vec<u8>> = (0 .. spec.width).map(|y| {
+        get_both_allowed_chars(left_hint, right_hint, &all_allowed_chars)
+    }).collect::<anyhow::Result<Vec<_>>>()?;
+    let col_allowed_chars: Vec<ClassUnicode> = (0 .. spec.width).map(|y| {
         // For each row...
         let top_hint = spec.top_hints.as_ref().and_then(|x| x.get(y)).and_then(|x| x.as_ref());
         let bottom_hint = spec.bottom_hints.as_ref().and_then(|x| x.get(y)).and_then(|x| x.as_ref());
-        get_both_allowed_chars(top_hint, bottom_hint, &all_allowed_chars).unwrap()
-    }).collect();
+        get_both_allowed_chars(top_hint, bottom_hint, &all_allowed_chars)
+    }).collect::<anyhow::Result<Vec<_>>>()?;
     println!("More finely:");
     for y in 0 .. spec.height {
-        println!("  Row #{}: {:?}", y + 1, row_allowed_chars[y].iter().map(|&x| x as char).collect::<String>());
+        println!("  Row #{}: {:?}", y + 1, row_allowed_chars[y]);
     }
     for x in 0 .. spec.width {
-        println!("  Col #{}: {:?}", x + 1, col_allowed_chars[x].iter().map(|&x| x as char).collect::<String>());
+        println!("  Col #{}: {:?}", x + 1, col_allowed_chars[x]);
     }
     // Print a board to put characters on
     print!("╔");
@@ -461,7 +500,7 @@ fn main() -> anyhow::Result<()> {
     print!("╝\n");
     let mut board = Board::new(spec.width, spec.height, row_allowed_chars, col_allowed_chars);
     while board.still_undecided() {
-        if !board.make_progress(&hints) {
+        if !board.make_progress(&hints)? {
             return Err(anyhow!("We couldn't make any more progress. Stumped!"));
         }
     }